@@ -0,0 +1,214 @@
+// A generational-index arena: handles stay valid across frames (unlike a plain `Vec` index,
+// which silently points at whatever now lives at that slot once the original entry is removed).
+
+use rayon::prelude::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct EntityId {
+    index: u32,
+    generation: u32,
+}
+
+impl EntityId {
+    // Only meant for handles crossing a boundary that can't carry the `EntityId` type itself,
+    // e.g. a WASM guest reporting back which entity it wants to hit.
+    pub fn from_raw(index: u32, generation: u32) -> Self {
+        EntityId { index, generation }
+    }
+
+    pub fn to_raw(self) -> (u32, u32) {
+        (self.index, self.generation)
+    }
+}
+
+#[derive(Clone)]
+pub struct Arena<T> {
+    entries: Vec<Option<T>>,
+    generations: Vec<u32>,
+    free_list: Vec<usize>,
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Self {
+        Arena {
+            entries: Vec::new(),
+            generations: Vec::new(),
+            free_list: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> EntityId {
+        if let Some(index) = self.free_list.pop() {
+            self.entries[index] = Some(value);
+            EntityId {
+                index: index as u32,
+                generation: self.generations[index],
+            }
+        } else {
+            let index = self.entries.len();
+            self.entries.push(Some(value));
+            self.generations.push(0);
+            EntityId {
+                index: index as u32,
+                generation: 0,
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: EntityId) -> Option<T> {
+        if self.generations.get(id.index as usize).copied() != Some(id.generation) {
+            return None;
+        }
+
+        let value = self.entries.get_mut(id.index as usize)?.take();
+        if value.is_some() {
+            self.generations[id.index as usize] += 1;
+            self.free_list.push(id.index as usize);
+        }
+        value
+    }
+
+    pub fn get(&self, id: EntityId) -> Option<&T> {
+        if self.generations.get(id.index as usize).copied() != Some(id.generation) {
+            return None;
+        }
+        self.entries[id.index as usize].as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: EntityId) -> Option<&mut T> {
+        if self.generations.get(id.index as usize).copied() != Some(id.generation) {
+            return None;
+        }
+        self.entries[id.index as usize].as_mut()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (EntityId, &T)> {
+        let generations = &self.generations;
+        self.entries.iter().enumerate().filter_map(move |(i, e)| {
+            e.as_ref().map(|v| {
+                (
+                    EntityId {
+                        index: i as u32,
+                        generation: generations[i],
+                    },
+                    v,
+                )
+            })
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (EntityId, &mut T)> {
+        let generations = &self.generations;
+        self.entries.iter_mut().enumerate().filter_map(move |(i, e)| {
+            e.as_mut().map(|v| {
+                (
+                    EntityId {
+                        index: i as u32,
+                        generation: generations[i],
+                    },
+                    v,
+                )
+            })
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.iter().filter(|e| e.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Total number of slots ever allocated, including vacated ones. Lets callers that want to
+    // walk every slot by raw index know where to stop.
+    pub fn slot_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    // The handle currently occupying `index`, or None if that slot is empty.
+    pub fn id_at(&self, index: usize) -> Option<EntityId> {
+        self.entries.get(index)?.as_ref()?;
+        Some(EntityId {
+            index: index as u32,
+            generation: self.generations[index],
+        })
+    }
+}
+
+impl<T: Send> Arena<T> {
+    // Parallel counterpart to `iter_mut`, for callers whose per-item work is independent (no
+    // cross-entity mutation) and can be split across threads by rayon.
+    pub fn par_iter_mut(&mut self) -> impl ParallelIterator<Item = (EntityId, &mut T)> {
+        let generations = &self.generations;
+        self.entries
+            .par_iter_mut()
+            .enumerate()
+            .filter_map(move |(i, e)| {
+                e.as_mut().map(|v| {
+                    (
+                        EntityId {
+                            index: i as u32,
+                            generation: generations[i],
+                        },
+                        v,
+                    )
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_slot_id_goes_stale_after_reuse() {
+        let mut arena = Arena::new();
+        let first = arena.insert("first");
+
+        arena.remove(first);
+        assert_eq!(arena.get(first), None);
+
+        // Reinserting reuses the freed slot, but bumps its generation, so the old handle must
+        // not be able to see the new occupant.
+        let second = arena.insert("second");
+        assert_eq!(second.index, first.index);
+        assert_ne!(second.generation, first.generation);
+
+        assert_eq!(arena.get(first), None);
+        assert_eq!(arena.get(second), Some(&"second"));
+    }
+
+    #[test]
+    fn remove_is_a_no_op_for_a_stale_id() {
+        let mut arena = Arena::new();
+        let first = arena.insert("first");
+
+        arena.remove(first);
+        let second = arena.insert("second");
+        assert_eq!(second.index, first.index);
+
+        // `first` is now stale (same slot, old generation): removing it must not delete the
+        // entity that now lives there.
+        assert_eq!(arena.remove(first), None);
+        assert_eq!(arena.get(second), Some(&"second"));
+    }
+
+    #[test]
+    fn iter_and_len_skip_vacated_slots() {
+        let mut arena = Arena::new();
+        let a = arena.insert(1);
+        let _b = arena.insert(2);
+        arena.remove(a);
+
+        assert_eq!(arena.len(), 1);
+        assert_eq!(arena.iter().map(|(_, v)| *v).collect::<Vec<_>>(), vec![2]);
+    }
+}
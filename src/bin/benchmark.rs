@@ -0,0 +1,17 @@
+// Builds a large populated Game and times a batch of ticks end to end, so the rayon speedup from
+// parallelizing Game::update's per-entity pass is measurable and regressions are caught.
+//
+// Run with: cargo run --release --bin benchmark
+
+fn main() {
+    const ENTITY_COUNT: usize = 2_000;
+    const TICKS: usize = 200;
+
+    let elapsed = rl_approaches::simple1::benchmark(TICKS, ENTITY_COUNT);
+
+    println!(
+        "{TICKS} ticks over {ENTITY_COUNT} entities: {:?} total, {:?}/tick",
+        elapsed,
+        elapsed / TICKS as u32
+    );
+}
@@ -0,0 +1,197 @@
+// Lets entity AI be supplied as a sandboxed WebAssembly module instead of hardcoded Rust. Guest
+// code never touches engine state directly: it reads the world through host-imported functions
+// and requests changes by pushing `GuestAction`s, which the caller turns into ordinary
+// `SideEffect`s after validating them exactly like any other entity's update.
+
+use wasmtime::{Caller, Config, Engine, Linker, Module, Store, TypedFunc};
+
+use crate::math::Vec2;
+
+// Index into `Game::brains`. Kept as a plain Copy handle so `PlayerData`/`MonsterData` can hold
+// one without losing `Copy` themselves (the `Brain` behind it very much isn't).
+pub type BrainId = usize;
+
+// What a module is asking the engine to do this tick. The caller still has to validate these
+// against the real game state (a guest can request anything, legal or not) before they become
+// `SideEffect`s.
+#[derive(Clone, Copy)]
+pub enum GuestAction {
+    MoveTo { x: f32, y: f32 },
+    Attack { entity_index: u32, entity_generation: u32 },
+}
+
+// Read-only snapshot of what a brain is allowed to see this tick, plus the actions it has
+// requested so far. Lives in the wasmtime `Store` so the host-imported functions below can read
+// and write it without the guest ever getting a raw pointer into engine memory.
+struct HostState {
+    self_pos: Vec2,
+    // (x, y, is_player) for every entity within the brain's sight radius, itself excluded.
+    nearby: Vec<(f32, f32, bool)>,
+    nearby_ids: Vec<(u32, u32)>,
+    // (x, y, is_walkable) for every map tile in a small square around the brain (narrower than
+    // the entity sight radius above), so a guest can plan
+    // a `move_to` that actually lands on open ground instead of guessing and getting ignored.
+    local_tiles: Vec<(f32, f32, bool)>,
+    actions: Vec<GuestAction>,
+}
+
+pub struct Brain {
+    store: Store<HostState>,
+    update_fn: TypedFunc<(), ()>,
+    cycle_budget: u64,
+}
+
+// Builds the only kind of `Engine` `Brain::load` accepts: fuel metering has to be switched on at
+// the `Config` level before `Store::set_fuel` will do anything but error out, and there's no way
+// to flip that on after the fact. Always get the engine passed to `Brain::load` from here.
+pub fn engine_for_brains() -> anyhow::Result<Engine> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    Ok(Engine::new(&config)?)
+}
+
+impl Brain {
+    // Loads a compiled module and wires up the host imports it needs:
+    //   env.nearby_count() -> i32
+    //   env.nearby_at(index: i32) -> (f32, f32, i32)     // x, y, is_player
+    //   env.nearby_id_at(index: i32) -> (i32, i32)       // entity_index, entity_generation
+    //   env.tile_count() -> i32
+    //   env.tile_at(index: i32) -> (f32, f32, i32)       // x, y, is_walkable
+    //   env.move_to(x: f32, y: f32)
+    //   env.attack(entity_index: i32, entity_generation: i32)
+    // `cycle_budget` is the fuel handed out per tick; a module that burns through it is
+    // preempted and simply forfeits its turn rather than stalling the host.
+    //
+    // `engine` MUST have been built with `engine_for_brains` (or otherwise have fuel consumption
+    // enabled via `Config::consume_fuel`): the `set_fuel` call below returns an error otherwise,
+    // and `load` fails with that error.
+    pub fn load(engine: &Engine, module: &Module, cycle_budget: u64) -> anyhow::Result<Self> {
+        let mut store = Store::new(
+            engine,
+            HostState {
+                self_pos: Vec2 { x: 0.0, y: 0.0 },
+                nearby: Vec::new(),
+                nearby_ids: Vec::new(),
+                local_tiles: Vec::new(),
+                actions: Vec::new(),
+            },
+        );
+        store.set_fuel(cycle_budget)?;
+
+        let mut linker = Linker::new(engine);
+        linker.func_wrap("env", "nearby_count", |caller: Caller<'_, HostState>| {
+            caller.data().nearby.len() as i32
+        })?;
+        linker.func_wrap(
+            "env",
+            "nearby_at",
+            |caller: Caller<'_, HostState>, index: i32| -> (f32, f32, i32) {
+                match caller.data().nearby.get(index as usize) {
+                    Some((x, y, is_player)) => (*x, *y, *is_player as i32),
+                    None => (0.0, 0.0, 0),
+                }
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "nearby_id_at",
+            |caller: Caller<'_, HostState>, index: i32| -> (i32, i32) {
+                match caller.data().nearby_ids.get(index as usize) {
+                    Some((entity_index, entity_generation)) => {
+                        (*entity_index as i32, *entity_generation as i32)
+                    }
+                    None => (0, 0),
+                }
+            },
+        )?;
+        linker.func_wrap("env", "tile_count", |caller: Caller<'_, HostState>| {
+            caller.data().local_tiles.len() as i32
+        })?;
+        linker.func_wrap(
+            "env",
+            "tile_at",
+            |caller: Caller<'_, HostState>, index: i32| -> (f32, f32, i32) {
+                match caller.data().local_tiles.get(index as usize) {
+                    Some((x, y, is_walkable)) => (*x, *y, *is_walkable as i32),
+                    None => (0.0, 0.0, 0),
+                }
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "move_to",
+            |mut caller: Caller<'_, HostState>, x: f32, y: f32| {
+                caller.data_mut().actions.push(GuestAction::MoveTo { x, y });
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "attack",
+            |mut caller: Caller<'_, HostState>, entity_index: i32, entity_generation: i32| {
+                caller.data_mut().actions.push(GuestAction::Attack {
+                    entity_index: entity_index as u32,
+                    entity_generation: entity_generation as u32,
+                });
+            },
+        )?;
+
+        let instance = linker.instantiate(&mut store, module)?;
+        let update_fn = instance.get_typed_func::<(), ()>(&mut store, "update")?;
+
+        Ok(Brain {
+            store,
+            update_fn,
+            cycle_budget,
+        })
+    }
+
+    // Presents this tick's world view to the guest, runs its `update` export under a fuel cap,
+    // and returns whatever actions it requested. `nearby` pairs each visible entity's position
+    // and player-ness with the raw `(index, generation)` the guest must echo back to target it.
+    // `local_tiles` is the walkability of each map tile within sight, so a guest can avoid walls
+    // instead of guessing and having an illegal `move_to` silently ignored.
+    pub fn tick(
+        &mut self,
+        self_pos: Vec2,
+        nearby: Vec<(Vec2, bool, (u32, u32))>,
+        local_tiles: Vec<(f32, f32, bool)>,
+    ) -> Vec<GuestAction> {
+        {
+            let state = self.store.data_mut();
+            state.self_pos = self_pos;
+            state.nearby = nearby.iter().map(|(pos, is_player, _)| (pos.x, pos.y, *is_player)).collect();
+            state.nearby_ids = nearby.iter().map(|(_, _, id)| *id).collect();
+            state.local_tiles = local_tiles;
+            state.actions.clear();
+        }
+
+        // Reset the budget every tick: a module that forfeits a turn by running dry doesn't stay
+        // starved forever, it just gets preempted for that one tick.
+        if self.store.set_fuel(self.cycle_budget).is_err() {
+            return Vec::new();
+        }
+
+        match self.update_fn.call(&mut self.store, ()) {
+            Ok(()) => std::mem::take(&mut self.store.data_mut().actions),
+            Err(_trap) => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runaway_guest_is_preempted_by_fuel() {
+        let engine = engine_for_brains().unwrap();
+        let wat = r#"(module (func (export "update") (loop (br 0))))"#;
+        let module = Module::new(&engine, wat).unwrap();
+        let mut brain = Brain::load(&engine, &module, 1_000).unwrap();
+
+        // An infinite loop would hang forever without fuel metering; it should instead trap once
+        // the budget runs out and the tick just forfeits with no actions.
+        let actions = brain.tick(Vec2 { x: 0.0, y: 0.0 }, Vec::new(), Vec::new());
+        assert!(actions.is_empty());
+    }
+}
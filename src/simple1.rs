@@ -1,229 +1,1319 @@
-use crate::math::{vec2, Vec2};
-
-// Map is intended for stuff that does not move nor update and it is optimized to be retrieved by position ie. a grid of size x size (water, walls, floors, doors?)
-struct Map {
-    size: usize,
-    map: Vec<MapClass>,
-}
-
-impl Map {
-    fn new(size: usize) -> Self {
-        let map = Vec::with_capacity(size * size);
-        Map { size, map }
-    }
-
-    fn class_at(&self, pos: Vec2) -> Option<MapClass> {
-        todo!()
-    }
-
-    // Check if a position can be occupied by an Entity ie. maybe there's a wall there?
-    fn validate_move(&self, pos: Vec2) -> bool {
-        if let Some(class) = self.class_at(pos) {
-            match class {
-                MapClass::Wall | MapClass::ClosedDoor | MapClass::None => false,
-                _ => true,
-            }
-        } else {
-            true
-        }
-    }
-}
-
-enum MapClass {
-    None,
-    Water,
-    Floor,
-    Wall,
-    ClosedDoor,
-    OpenDoor,
-}
-
-#[derive(Clone, Copy)]
-struct PlayerData {}
-
-#[derive(Clone, Copy)]
-struct MonsterData {}
-
-#[derive(Clone, Copy)]
-struct ItemData {}
-
-type EntityId = usize;
-
-#[derive(Clone, Copy)]
-enum EntityClass {
-    Player(PlayerData),
-    Monster(MonsterData),
-    Item(ItemData),
-}
-
-// Entities are things that move and update themselves (Players, Monsters etc.)
-#[derive(Clone, Copy)]
-struct Entity {
-    pos: Vec2,
-    health: f32,
-    class: EntityClass,
-}
-
-impl Entity {
-    fn update(&mut self, my_id: EntityId, entities: &[Entity], map: &Map) -> Vec<SideEffect> {
-        match &mut self.class {
-            EntityClass::Player(_player) => {
-                // These 2 consts could be coming from the PlayerData
-                const ATTACK_DISTANCE: f32 = 2.0;
-                const MY_ATTACK_STRENGTH: f32 = 0.25;
-
-                // Example move
-                let new_pos = vec2(self.pos.x + 0.1, self.pos.y);
-
-                if map.validate_move(new_pos) {
-                    // We can move there
-                    self.pos = new_pos;
-                }
-
-                // Example Attack anything that is close enough to us
-                let mut side_effects = Vec::new();
-                entities
-                    .iter()
-                    .enumerate()
-                    .filter(|(i, _)| *i != my_id)
-                    .for_each(|(i, e)| {
-                        if self.pos.distance(e.pos) < ATTACK_DISTANCE {
-                            side_effects.push(SideEffect::Attack {
-                                entity0: my_id,
-                                entity1: i,
-                                strength: MY_ATTACK_STRENGTH,
-                            });
-                        }
-                    });
-
-                side_effects
-            }
-            EntityClass::Monster(_) => todo!(),
-            EntityClass::Item(_) => todo!(),
-        }
-    }
-}
-
-#[derive(Clone, Copy)]
-enum SideEffect {
-    Attack {
-        entity0: EntityId,
-        entity1: EntityId,
-        strength: f32,
-    },
-    MapAttack {
-        // This is something that will influence the map
-        entity0: EntityId,
-        map_pos: Vec2,
-        strength: f32,
-    },
-}
-
-struct Game {
-    map: Map,
-    entities: Vec<Entity>,
-}
-
-impl Game {
-    fn new() -> Self {
-        let map = Map::new(1024);
-        let entities = Vec::new();
-
-        Game { map, entities }
-    }
-
-    fn apply_side_effect(&mut self, effect: SideEffect) {
-        match effect {
-            SideEffect::Attack {
-                entity1, strength, ..
-            } => {
-                self.entities[entity1].health -= strength;
-            }
-            SideEffect::MapAttack {
-                entity0,
-                map_pos,
-                strength,
-            } => {
-                // We are &mut self so map is mut and we can change it as we like ie. change a Wall into a Floor or into a DestroyedWall
-                todo!()
-            }
-        }
-    }
-
-    fn update(&mut self) -> bool {
-        // As long as Entity is trivial to clone this is ok and will pay dividends later if the update map
-        // is called in parallel with rayon
-        let entities0 = self.entities.clone();
-
-        // Update entities and collect side effects vectors
-        let side_effects: Vec<Vec<SideEffect>> = self
-            .entities
-            .iter_mut()
-            .enumerate()
-            .map(|(i, e)| e.update(i, &entities0, &self.map))
-            .collect();
-
-        // Apply side effects
-        side_effects.iter().for_each(|eff| {
-            eff.iter().for_each(|side_effect| {
-                self.apply_side_effect(*side_effect);
-            })
-        });
-
-        // Last step, kill entities. After this line all the EntityIds are to be considered invalid
-        self.entities.retain(|e| e.health <= 0.0);
-
-        // TODO
-        // Because EntityId is simply an index into the self.entity vector these Ids are only valid within a frame
-        // Thus entities cannot store an Id in them for complex logic that extends more than 1 frame
-        // If that is a necessity then sommething else needs to be used (Generational Indices, maps etc)
-
-        // Keep running if there are at least 2 entities alive
-        self.entities.len() > 1
-    }
-
-    // An alternative way to update entities is by using indices everywhere
-    // Pros:
-    // - everything can be done in one pass, including map alternations
-    // Cons:
-    // - very error prone and verbose with self.entities[x] everywhere
-    // - impossible to execute in parallel
-    // - the entire Entity update logic goes in this func
-    fn update_indexed(&mut self) -> bool {
-        for i in 0..self.entities.len() {
-            match self.entities[i].class {
-                // These 2 consts could be coming from the PlayerData
-                EntityClass::Player(player) => {
-                    const ATTACK_DISTANCE: f32 = 2.0;
-                    const MY_ATTACK_STRENGTH: f32 = 0.25;
-
-                    // Do bad things to other entities
-                    for ei in 0..self.entities.len() {
-                        // Check it's not me
-                        if i != ei {
-                            if self.entities[i].pos.distance(self.entities[ei].pos)
-                                < ATTACK_DISTANCE
-                            {
-                                // Just do it
-                                self.entities[ei].health -= MY_ATTACK_STRENGTH;
-                            }
-                        }
-                    }
-                }
-                EntityClass::Monster(_) => todo!(),
-                EntityClass::Item(_) => todo!(),
-            }
-        }
-
-        // Last step, kill entities.
-        self.entities.retain(|e| e.health <= 0.0);
-
-        self.entities.len() > 1
-    }
-}
-
-pub fn run() {
-    let mut game = Game::new();
-
-    while game.update() {}
-}
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rayon::prelude::*;
+use smallvec::SmallVec;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::arena::{Arena, EntityId};
+use crate::brain::{engine_for_brains, Brain, BrainId, GuestAction};
+use crate::math::{vec2, Vec2};
+
+// Map is intended for stuff that does not move nor update and it is optimized to be retrieved by position ie. a grid of size x size (water, walls, floors, doors?)
+struct Map {
+    size: usize,
+    map: Vec<MapClass>,
+}
+
+impl Map {
+    fn new(size: usize) -> Self {
+        let map = vec![MapClass::Floor; size * size];
+        Map { size, map }
+    }
+
+    // Row-major index of `pos` in `map`, or None if `pos` falls outside [0, size) on either axis.
+    fn index_of(&self, pos: Vec2) -> Option<usize> {
+        if pos.x < 0.0 || pos.y < 0.0 {
+            return None;
+        }
+
+        let x = pos.x as usize;
+        let y = pos.y as usize;
+
+        if x >= self.size || y >= self.size {
+            return None;
+        }
+
+        Some(y * self.size + x)
+    }
+
+    fn class_at(&self, pos: Vec2) -> Option<MapClass> {
+        self.index_of(pos).map(|idx| self.map[idx])
+    }
+
+    // Check if a position can be occupied by an Entity ie. maybe there's a wall there?
+    fn validate_move(&self, pos: Vec2) -> bool {
+        // Off-grid is never walkable: class_at already returns None for exactly this case, so
+        // treating it as open here would let pathing/wandering escape the map's bounds.
+        match self.class_at(pos) {
+            Some(class) => !matches!(class, MapClass::Wall | MapClass::ClosedDoor | MapClass::None),
+            None => false,
+        }
+    }
+
+    // The 8 cardinal/diagonal neighbors of `pos` that can actually be walked onto, paired with
+    // the cost of stepping there (1.0 cardinal, ~1.41 diagonal), ready to feed a_star. Gated
+    // through `occupancy` rather than bare terrain so path/step selection routes around other
+    // entities instead of only learning about them at the final move-gate in `Entity::update`.
+    // `goal`, when given, is exempted from the occupancy check (terrain only): a_star's target
+    // tile is always occupied by the target itself, and that tile needs to be reachable for the
+    // search to terminate — the final move-gate in `Entity::update` still blocks ever actually
+    // stepping onto it while it's occupied.
+    fn get_available_exits(
+        &self,
+        occupancy: &Occupancy,
+        pos: Vec2,
+        goal: Option<Vec2>,
+    ) -> SmallVec<[(Vec2, f32); 8]> {
+        const DIRS: [(f32, f32, f32); 8] = [
+            (-1.0, 0.0, 1.0),
+            (1.0, 0.0, 1.0),
+            (0.0, -1.0, 1.0),
+            (0.0, 1.0, 1.0),
+            (-1.0, -1.0, std::f32::consts::SQRT_2),
+            (-1.0, 1.0, std::f32::consts::SQRT_2),
+            (1.0, -1.0, std::f32::consts::SQRT_2),
+            (1.0, 1.0, std::f32::consts::SQRT_2),
+        ];
+
+        let mut exits = SmallVec::new();
+        for (dx, dy, cost) in DIRS {
+            let neighbor = vec2(pos.x + dx, pos.y + dy);
+            let is_goal = goal.is_some_and(|g| tile_key(g) == tile_key(neighbor));
+            let walkable = if is_goal {
+                self.validate_move(neighbor)
+            } else {
+                occupancy.validate_move(self, neighbor)
+            };
+            if walkable {
+                exits.push((neighbor, cost));
+            }
+        }
+        exits
+    }
+
+    // Standard A* over the grid, using Euclidean distance as both edge cost and heuristic so it
+    // stays admissible with diagonal movement. Returns the path from `start` to `goal` inclusive.
+    fn a_star(&self, occupancy: &Occupancy, start: Vec2, goal: Vec2) -> Option<Vec<Vec2>> {
+        let goal_key = tile_key(goal);
+
+        let mut open = BinaryHeap::new();
+        open.push(OpenEntry {
+            f: start.distance(goal),
+            pos: start,
+        });
+
+        let mut came_from: HashMap<(i32, i32), Vec2> = HashMap::new();
+        let mut g_score: HashMap<(i32, i32), f32> = HashMap::new();
+        g_score.insert(tile_key(start), 0.0);
+
+        while let Some(OpenEntry { pos: current, .. }) = open.pop() {
+            if tile_key(current) == goal_key {
+                return Some(reconstruct_path(&came_from, current));
+            }
+
+            let current_g = g_score[&tile_key(current)];
+            for (neighbor, cost) in self.get_available_exits(occupancy, current, Some(goal)) {
+                let tentative_g = current_g + cost;
+                let neighbor_key = tile_key(neighbor);
+
+                if tentative_g < *g_score.get(&neighbor_key).unwrap_or(&f32::INFINITY) {
+                    came_from.insert(neighbor_key, current);
+                    g_score.insert(neighbor_key, tentative_g);
+                    open.push(OpenEntry {
+                        f: tentative_g + neighbor.distance(goal),
+                        pos: neighbor,
+                    });
+                }
+            }
+        }
+
+        None
+    }
+}
+
+// Grid coordinates are used as hash map keys for a_star's came_from/g_score maps since Vec2 (f32, f32)
+// is neither Eq nor Hash.
+fn tile_key(pos: Vec2) -> (i32, i32) {
+    (pos.x.round() as i32, pos.y.round() as i32)
+}
+
+fn reconstruct_path(came_from: &HashMap<(i32, i32), Vec2>, mut current: Vec2) -> Vec<Vec2> {
+    let mut path = vec![current];
+    while let Some(&prev) = came_from.get(&tile_key(current)) {
+        path.push(prev);
+        current = prev;
+    }
+    path.reverse();
+    path
+}
+
+// BinaryHeap is a max-heap, so we flip the f-score comparison to pop the lowest f first.
+// Ordered (and compared) by `f` alone; `pos` just rides along and has no bearing on heap order.
+#[derive(Clone, Copy)]
+struct OpenEntry {
+    f: f32,
+    pos: Vec2,
+}
+
+impl PartialEq for OpenEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.f == other.f
+    }
+}
+
+impl Eq for OpenEntry {}
+
+impl Ord for OpenEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.f.partial_cmp(&self.f).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for OpenEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum MapClass {
+    None,
+    Water,
+    Floor,
+    Wall,
+    ClosedDoor,
+    OpenDoor,
+}
+
+#[derive(Clone, Copy)]
+struct PlayerData {
+    // A human/scripted player normally drives itself; this is only set for a WASM-controlled
+    // stand-in (e.g. a replay bot).
+    brain: Option<BrainId>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Faction {
+    Player,
+    Hostile,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AiState {
+    Approach,
+    Flee,
+    MeleeAdjacent,
+    Wander,
+}
+
+#[derive(Clone, Copy)]
+struct MonsterData {
+    ai_state: AiState,
+    faction: Faction,
+    aggro_range: f32,
+    // Last entity we decided to chase/flee; re-checked (and re-picked if stale or dead) each
+    // frame now that EntityId survives across frames.
+    target: Option<EntityId>,
+    // When set, this monster's turn is handed to a sandboxed WASM module instead of the
+    // hardcoded approach/flee/melee/wander state machine below.
+    brain: Option<BrainId>,
+    kind: MonsterKind,
+}
+
+#[derive(Clone, Copy)]
+enum MonsterKind {
+    Rat,
+    Goblin,
+    Troll,
+}
+
+#[derive(Clone, Copy)]
+enum ItemKind {
+    Gold,
+    Potion,
+    Scroll,
+    Weapon,
+    Gem,
+}
+
+// A monster's loot, split the way the tutorials' box/rare drop tables usually are: one pick from
+// the common pool (cumulative-weight sampling), plus each rare entry independently rolled as a
+// 1-in-`one_in_n` chance.
+struct DropTable {
+    common: Vec<(ItemKind, u32)>,
+    rare: Vec<(ItemKind, u32)>,
+}
+
+fn drop_table_for(kind: MonsterKind) -> DropTable {
+    match kind {
+        MonsterKind::Rat => DropTable {
+            common: vec![(ItemKind::Gold, 8), (ItemKind::Potion, 2)],
+            rare: vec![(ItemKind::Gem, 50)],
+        },
+        MonsterKind::Goblin => DropTable {
+            common: vec![(ItemKind::Gold, 5), (ItemKind::Potion, 3), (ItemKind::Scroll, 2)],
+            rare: vec![(ItemKind::Weapon, 20)],
+        },
+        MonsterKind::Troll => DropTable {
+            common: vec![(ItemKind::Gold, 4), (ItemKind::Weapon, 4), (ItemKind::Scroll, 2)],
+            rare: vec![(ItemKind::Gem, 8)],
+        },
+    }
+}
+
+// Rolls `table` once: at most one common-pool item, plus zero or more independently-rolled rare
+// ones, all dropped at `pos`. Takes the RNG rather than reaching for `rand::thread_rng()` so
+// callers (and tests) can pin down the roll with a seeded one.
+fn roll_loot(table: &DropTable, pos: Vec2, rng: &mut impl Rng) -> Vec<SideEffect> {
+    let mut drops = Vec::new();
+
+    let total_weight: u32 = table.common.iter().map(|(_, weight)| weight).sum();
+    if total_weight > 0 {
+        let mut roll = rng.gen_range(0..total_weight);
+        for (item, weight) in &table.common {
+            if roll < *weight {
+                drops.push(SideEffect::SpawnItem { pos, item: *item });
+                break;
+            }
+            roll -= weight;
+        }
+    }
+
+    for (item, one_in_n) in &table.rare {
+        if *one_in_n > 0 && rng.gen_range(0..*one_in_n) == 0 {
+            drops.push(SideEffect::SpawnItem { pos, item: *item });
+        }
+    }
+
+    drops
+}
+
+#[derive(Clone, Copy)]
+struct ItemData {
+    kind: ItemKind,
+}
+
+#[derive(Clone, Copy)]
+enum EntityClass {
+    Player(PlayerData),
+    Monster(MonsterData),
+    Item(ItemData),
+}
+
+#[derive(Clone, Copy)]
+struct CombatStats {
+    max_hp: i32,
+    hp: i32,
+    defense: i32,
+    power: i32,
+}
+
+// Incoming hits accumulated over a frame so several attackers landing on the same tick all
+// count, instead of each `apply_side_effect` call racing the others to subtract from `hp`.
+#[derive(Default)]
+struct SufferDamage {
+    amounts: Vec<i32>,
+}
+
+fn damage_dealt(attacker: &CombatStats, defender: &CombatStats) -> i32 {
+    (attacker.power - defender.defense).max(1)
+}
+
+// How close an attacker needs to be to land a hit; shared by the hardcoded monster AI and
+// `run_brain` so a WASM brain can't reach further than any other attacker.
+const MELEE_DISTANCE: f32 = 1.5;
+
+// Entities are things that move and update themselves (Players, Monsters etc.)
+#[derive(Clone, Copy)]
+struct Entity {
+    pos: Vec2,
+    stats: CombatStats,
+    class: EntityClass,
+}
+
+impl Entity {
+    fn update(
+        &mut self,
+        my_id: EntityId,
+        entities: &Arena<Entity>,
+        map: &Map,
+        occupancy: &Occupancy,
+        brains: &mut [Brain],
+    ) -> Vec<SideEffect> {
+        match &mut self.class {
+            EntityClass::Player(player) => {
+                if let Some(brain_id) = player.brain {
+                    let (new_pos, side_effects) =
+                        run_brain(brain_id, brains, my_id, self.pos, entities, map, occupancy);
+                    self.pos = new_pos;
+                    return side_effects;
+                }
+
+                // This const could be coming from the PlayerData
+                const ATTACK_DISTANCE: f32 = 2.0;
+
+                // Example move
+                let new_pos = vec2(self.pos.x + 0.1, self.pos.y);
+
+                if occupancy.validate_move(map, new_pos) {
+                    // We can move there
+                    self.pos = new_pos;
+                }
+
+                // Example Attack anything that is close enough to us and can actually fight back
+                // (Items have no faction and shouldn't be targeted, e.g. dropped loot sitting on
+                // the tile where a monster just died next to us).
+                let mut side_effects: Vec<SideEffect> = occupancy
+                    .entities_in_radius(map, entities, self.pos, ATTACK_DISTANCE)
+                    .into_iter()
+                    .filter(|id| *id != my_id)
+                    .filter(|id| entities.get(*id).is_some_and(|e| entity_faction(e).is_some()))
+                    .map(|id| SideEffect::Attack {
+                        entity0: my_id,
+                        entity1: id,
+                    })
+                    .collect();
+
+                // Standing on loot picks it up; the reverse of the filter above, since the only
+                // thing worth targeting here is an Item.
+                const PICKUP_DISTANCE: f32 = 0.5;
+                side_effects.extend(
+                    occupancy
+                        .entities_in_radius(map, entities, self.pos, PICKUP_DISTANCE)
+                        .into_iter()
+                        .filter(|id| *id != my_id)
+                        .filter(|id| {
+                            entities.get(*id).is_some_and(|e| matches!(e.class, EntityClass::Item(_)))
+                        })
+                        .map(|id| SideEffect::Pickup {
+                            entity0: my_id,
+                            entity1: id,
+                        }),
+                );
+
+                side_effects
+            }
+            EntityClass::Monster(monster) => {
+                if let Some(brain_id) = monster.brain {
+                    let (new_pos, side_effects) =
+                        run_brain(brain_id, brains, my_id, self.pos, entities, map, occupancy);
+                    self.pos = new_pos;
+                    return side_effects;
+                }
+
+                const FLEE_HEALTH_THRESHOLD: i32 = 10;
+
+                // Re-check the cached target: it may have died or wandered out of range since
+                // last frame, in which case we fall back to picking a fresh one.
+                let cached_target = monster.target.and_then(|id| {
+                    let target = entities.get(id)?;
+                    let dist = self.pos.distance(target.pos);
+                    (dist <= monster.aggro_range).then_some((id, target.pos, dist))
+                });
+
+                let target = cached_target.or_else(|| {
+                    nearest_hostile(
+                        self.pos,
+                        my_id,
+                        monster.faction,
+                        entities,
+                        occupancy,
+                        map,
+                        monster.aggro_range,
+                    )
+                });
+
+                monster.target = target.map(|(id, _, _)| id);
+
+                let (ai_state, new_pos, side_effects) = match target {
+                    Some((target_id, _, dist)) if dist < MELEE_DISTANCE => {
+                        melee_adjacent(self.pos, my_id, target_id)
+                    }
+                    Some((_, target_pos, _)) if self.stats.hp < FLEE_HEALTH_THRESHOLD => {
+                        flee(map, occupancy, self.pos, target_pos)
+                    }
+                    Some((_, target_pos, _)) => approach(map, occupancy, self.pos, target_pos),
+                    None => wander(map, occupancy, self.pos),
+                };
+
+                monster.ai_state = ai_state;
+
+                if occupancy.validate_move(map, new_pos) {
+                    self.pos = new_pos;
+                }
+
+                side_effects
+            }
+            // Items just sit where they dropped until picked up; they have nothing to do on
+            // their own turn.
+            EntityClass::Item(_) => Vec::new(),
+        }
+    }
+}
+
+// Sight radius a brain is shown each tick; kept modest since the snapshot is rebuilt and handed
+// across the wasm boundary every time.
+const BRAIN_SIGHT_RADIUS: f32 = 8.0;
+
+// Map tiles are cheaper to snapshot than entities but still not free, so a brain only gets a
+// small square around itself rather than the full `BRAIN_SIGHT_RADIUS`.
+const BRAIN_TILE_SIGHT_RADIUS: i32 = 4;
+
+// Walkability of every tile in a square of `BRAIN_TILE_SIGHT_RADIUS` around `pos`, using the same
+// occupancy check a requested `move_to` would be validated against (terrain plus whatever is
+// currently standing there), so a guest can tell in advance which moves would actually land.
+fn local_tiles_around(map: &Map, occupancy: &Occupancy, pos: Vec2) -> Vec<(f32, f32, bool)> {
+    let center_x = pos.x.round() as i32;
+    let center_y = pos.y.round() as i32;
+
+    let mut tiles = Vec::new();
+    for dy in -BRAIN_TILE_SIGHT_RADIUS..=BRAIN_TILE_SIGHT_RADIUS {
+        for dx in -BRAIN_TILE_SIGHT_RADIUS..=BRAIN_TILE_SIGHT_RADIUS {
+            let (Some(x), Some(y)) = (center_x.checked_add(dx), center_y.checked_add(dy)) else {
+                continue;
+            };
+            if x < 0 || y < 0 {
+                continue;
+            }
+
+            let tile_pos = vec2(x as f32, y as f32);
+            tiles.push((tile_pos.x, tile_pos.y, occupancy.validate_move(map, tile_pos)));
+        }
+    }
+    tiles
+}
+
+// Hands this entity's turn to its WASM brain: presents a read-only snapshot of nearby entities,
+// then validates and applies whatever it asks for exactly as if a hardcoded AI had asked for it.
+// A brain can request an illegal move or an out-of-range attack; the engine only accepts a
+// `MoveTo` that is a single validated step (same as every other mover) and an `Attack` within
+// `MELEE_DISTANCE` (same as every other attacker), and ignores anything else rather than
+// trusting the guest.
+fn run_brain(
+    brain_id: BrainId,
+    brains: &mut [Brain],
+    my_id: EntityId,
+    pos: Vec2,
+    entities: &Arena<Entity>,
+    map: &Map,
+    occupancy: &Occupancy,
+) -> (Vec2, Vec<SideEffect>) {
+    let nearby_ids = occupancy.entities_in_radius(map, entities, pos, BRAIN_SIGHT_RADIUS);
+
+    let Some(brain) = brains.get_mut(brain_id) else {
+        return (pos, Vec::new());
+    };
+
+    let nearby = nearby_ids
+        .iter()
+        .filter(|id| **id != my_id)
+        .filter_map(|&id| {
+            let e = entities.get(id)?;
+            Some((e.pos, matches!(e.class, EntityClass::Player(_)), id.to_raw()))
+        })
+        .collect();
+
+    let local_tiles = local_tiles_around(map, occupancy, pos);
+
+    let mut new_pos = pos;
+    let mut side_effects = Vec::new();
+
+    for action in brain.tick(pos, nearby, local_tiles) {
+        match action {
+            GuestAction::MoveTo { x, y } => {
+                let requested = vec2(x, y);
+                let requested_key = tile_key(requested);
+                // Only one step away, same as a player/monster move: a brain can't teleport
+                // across the map just because the destination tile happens to be open.
+                let is_one_step = map
+                    .get_available_exits(occupancy, pos, None)
+                    .iter()
+                    .any(|(exit, _)| tile_key(*exit) == requested_key);
+                if is_one_step {
+                    new_pos = requested;
+                }
+            }
+            GuestAction::Attack {
+                entity_index,
+                entity_generation,
+            } => {
+                let target = EntityId::from_raw(entity_index, entity_generation);
+                // In sight (BRAIN_SIGHT_RADIUS) isn't enough to land a hit; require the same
+                // MELEE_DISTANCE every other attacker is held to.
+                let in_melee_range = entities
+                    .get(target)
+                    .is_some_and(|e| pos.distance(e.pos) < MELEE_DISTANCE);
+                if nearby_ids.contains(&target) && in_melee_range {
+                    side_effects.push(SideEffect::Attack {
+                        entity0: my_id,
+                        entity1: target,
+                    });
+                }
+            }
+        }
+    }
+
+    (new_pos, side_effects)
+}
+
+// Nearest entity of a different faction than `my_faction` within `radius` of `pos` (skipping
+// `my_id` and anything factionless, like items), paired with its position and distance. Only
+// inspects tiles the spatial index says are occupied within that radius.
+fn nearest_hostile(
+    pos: Vec2,
+    my_id: EntityId,
+    my_faction: Faction,
+    entities: &Arena<Entity>,
+    occupancy: &Occupancy,
+    map: &Map,
+    radius: f32,
+) -> Option<(EntityId, Vec2, f32)> {
+    occupancy
+        .entities_in_radius(map, entities, pos, radius)
+        .into_iter()
+        .filter(|id| *id != my_id)
+        .filter_map(|id| {
+            let e = entities.get(id)?;
+            (entity_faction(e)? != my_faction).then(|| (id, e.pos, pos.distance(e.pos)))
+        })
+        .min_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(Ordering::Equal))
+}
+
+// Step one tile closer to `target` along the A* path, or stay put if no path exists. Routed
+// through `occupancy` so the path itself avoids tiles other entities are standing on, rather than
+// only discovering the blocker at the final move-gate in `Entity::update`.
+fn approach(map: &Map, occupancy: &Occupancy, pos: Vec2, target: Vec2) -> (AiState, Vec2, Vec<SideEffect>) {
+    let next_pos = map
+        .a_star(occupancy, pos, target)
+        .and_then(|path| path.get(1).copied())
+        .unwrap_or(pos);
+
+    (AiState::Approach, next_pos, Vec::new())
+}
+
+// Step to the reachable (and unoccupied) neighbor that puts the most distance between us and
+// `threat`.
+fn flee(map: &Map, occupancy: &Occupancy, pos: Vec2, threat: Vec2) -> (AiState, Vec2, Vec<SideEffect>) {
+    let next_pos = map
+        .get_available_exits(occupancy, pos, None)
+        .into_iter()
+        .map(|(exit, _)| exit)
+        .max_by(|a, b| {
+            a.distance(threat)
+                .partial_cmp(&b.distance(threat))
+                .unwrap_or(Ordering::Equal)
+        })
+        .unwrap_or(pos);
+
+    (AiState::Flee, next_pos, Vec::new())
+}
+
+// Stay put and swing at an already-adjacent target.
+fn melee_adjacent(
+    pos: Vec2,
+    entity0: EntityId,
+    entity1: EntityId,
+) -> (AiState, Vec2, Vec<SideEffect>) {
+    (
+        AiState::MeleeAdjacent,
+        pos,
+        vec![SideEffect::Attack { entity0, entity1 }],
+    )
+}
+
+// No target in range: shuffle to a random walkable, unoccupied neighbor.
+fn wander(map: &Map, occupancy: &Occupancy, pos: Vec2) -> (AiState, Vec2, Vec<SideEffect>) {
+    let next_pos = map
+        .get_available_exits(occupancy, pos, None)
+        .choose(&mut rand::thread_rng())
+        .map(|(exit, _)| *exit)
+        .unwrap_or(pos);
+
+    (AiState::Wander, next_pos, Vec::new())
+}
+
+// Which entities sit on each tile, plus a combined "can't walk here" bitset (walls/closed doors
+// ored with whatever is currently standing there), so attack/neighbor queries only ever look at
+// the handful of tiles within range instead of every entity in the game.
+struct Occupancy {
+    spatial: Vec<SmallVec<[EntityId; 4]>>,
+    blocked: Vec<bool>,
+    // Terrain-only blocking, computed once since the map itself never changes shape.
+    terrain_blocked: Vec<bool>,
+}
+
+impl Occupancy {
+    fn new(map: &Map) -> Self {
+        let terrain_blocked: Vec<bool> = (0..map.size * map.size)
+            .map(|idx| {
+                let pos = vec2((idx % map.size) as f32, (idx / map.size) as f32);
+                !map.validate_move(pos)
+            })
+            .collect();
+
+        Occupancy {
+            spatial: vec![SmallVec::new(); map.size * map.size],
+            blocked: terrain_blocked.clone(),
+            terrain_blocked,
+        }
+    }
+
+    // Re-derives `spatial`/`blocked` from the current entity positions. Call once per frame
+    // before running any queries against it.
+    fn rebuild(&mut self, map: &Map, entities: &Arena<Entity>) {
+        self.spatial.iter_mut().for_each(|slot| slot.clear());
+        self.blocked.copy_from_slice(&self.terrain_blocked);
+
+        for (id, e) in entities.iter() {
+            if let Some(idx) = map.index_of(e.pos) {
+                self.spatial[idx].push(id);
+                // Items can be picked up from underneath; only players/monsters block a tile.
+                if !matches!(e.class, EntityClass::Item(_)) {
+                    self.blocked[idx] = true;
+                }
+            }
+        }
+    }
+
+    fn validate_move(&self, map: &Map, pos: Vec2) -> bool {
+        match map.index_of(pos) {
+            Some(idx) => map.validate_move(pos) && !self.blocked[idx],
+            None => map.validate_move(pos),
+        }
+    }
+
+    // Every entity whose tile falls within the bounding box of `pos`..=`radius`, narrowed down
+    // to those actually within `radius` of `pos`.
+    fn entities_in_radius(
+        &self,
+        map: &Map,
+        entities: &Arena<Entity>,
+        pos: Vec2,
+        radius: f32,
+    ) -> Vec<EntityId> {
+        if map.size == 0 {
+            return Vec::new();
+        }
+
+        let max_index = map.size - 1;
+        let min_x = (pos.x - radius).max(0.0) as usize;
+        let min_y = (pos.y - radius).max(0.0) as usize;
+        let max_x = ((pos.x + radius).max(0.0) as usize).min(max_index);
+        let max_y = ((pos.y + radius).max(0.0) as usize).min(max_index);
+
+        let mut found = Vec::new();
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                for &id in &self.spatial[y * map.size + x] {
+                    if let Some(e) = entities.get(id) {
+                        if pos.distance(e.pos) <= radius {
+                            found.push(id);
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SideEffect {
+    Attack {
+        entity0: EntityId,
+        entity1: EntityId,
+    },
+    MapAttack {
+        // This is something that will influence the map
+        entity0: EntityId,
+        map_pos: Vec2,
+        strength: f32,
+    },
+    SpawnItem {
+        pos: Vec2,
+        item: ItemKind,
+    },
+    Pickup {
+        entity0: EntityId,
+        entity1: EntityId,
+    },
+}
+
+fn entity_brain(entity: &Entity) -> Option<BrainId> {
+    match entity.class {
+        EntityClass::Player(player) => player.brain,
+        EntityClass::Monster(monster) => monster.brain,
+        EntityClass::Item(_) => None,
+    }
+}
+
+// Items have no allegiance; only Players and Monsters take part in hostility checks. Players are
+// always Faction::Player (there's only ever one side to be on), monsters carry their own.
+fn entity_faction(entity: &Entity) -> Option<Faction> {
+    match entity.class {
+        EntityClass::Player(_) => Some(Faction::Player),
+        EntityClass::Monster(monster) => Some(monster.faction),
+        EntityClass::Item(_) => None,
+    }
+}
+
+struct Game {
+    map: Map,
+    entities: Arena<Entity>,
+    occupancy: Occupancy,
+    brains: Vec<Brain>,
+}
+
+impl Game {
+    fn new() -> Self {
+        let map = Map::new(1024);
+        let entities = Arena::new();
+        let occupancy = Occupancy::new(&map);
+
+        Game {
+            map,
+            entities,
+            occupancy,
+            brains: Vec::new(),
+        }
+    }
+
+    // Hands ownership of a loaded module to the game and returns the handle entities reference
+    // it by (see `MonsterData::brain`/`PlayerData::brain`).
+    fn register_brain(&mut self, brain: Brain) -> BrainId {
+        self.brains.push(brain);
+        self.brains.len() - 1
+    }
+
+    // Attacks don't land immediately; they queue damage in `suffer_damage` so every hit an
+    // entity takes this frame is known before any of them are subtracted from `hp`.
+    fn apply_side_effect(
+        &mut self,
+        effect: SideEffect,
+        suffer_damage: &mut HashMap<EntityId, SufferDamage>,
+    ) {
+        match effect {
+            SideEffect::Attack { entity0, entity1 } => {
+                let (Some(attacker), Some(defender)) =
+                    (self.entities.get(entity0), self.entities.get(entity1))
+                else {
+                    return;
+                };
+
+                let damage = damage_dealt(&attacker.stats, &defender.stats);
+                suffer_damage
+                    .entry(entity1)
+                    .or_default()
+                    .amounts
+                    .push(damage);
+            }
+            SideEffect::MapAttack { .. } => {
+                // Nothing constructs this variant yet; map-terrain interaction (e.g. breaking a
+                // Wall into a DestroyedWall) is future work. No-op rather than a live panic in
+                // the meantime.
+            }
+            SideEffect::SpawnItem { pos, item } => {
+                // Nudge onto a walkable neighbor if the drop spot is itself blocked (e.g. the
+                // monster died on a doorway tile).
+                let spawn_pos = if self.occupancy.validate_move(&self.map, pos) {
+                    pos
+                } else {
+                    self.map
+                        .get_available_exits(&self.occupancy, pos, None)
+                        .into_iter()
+                        .map(|(exit, _)| exit)
+                        .next()
+                        .unwrap_or(pos)
+                };
+
+                self.entities.insert(Entity {
+                    pos: spawn_pos,
+                    stats: CombatStats {
+                        max_hp: 1,
+                        hp: 1,
+                        defense: 0,
+                        power: 0,
+                    },
+                    class: EntityClass::Item(ItemData { kind: item }),
+                });
+            }
+            SideEffect::Pickup { entity0, entity1 } => {
+                let Some(item_entity) = self.entities.get(entity1) else {
+                    return;
+                };
+                let EntityClass::Item(item) = item_entity.class else {
+                    return;
+                };
+
+                // A Potion heals its picker back to full; everything else (Gold/Gem/Scroll/Weapon)
+                // is just collected for now — there's no inventory yet for them to go into.
+                if matches!(item.kind, ItemKind::Potion) {
+                    if let Some(picker) = self.entities.get_mut(entity0) {
+                        picker.stats.hp = picker.stats.max_hp;
+                    }
+                }
+
+                self.entities.remove(entity1);
+            }
+        }
+    }
+
+    // Subtracts every hit queued in `suffer_damage` from its target's `hp`, removes whoever it
+    // drops to zero or below, and rolls their loot table (if any) to spawn item entities in
+    // their place.
+    fn resolve_damage(&mut self, suffer_damage: HashMap<EntityId, SufferDamage>) {
+        for (id, suffer) in suffer_damage {
+            if let Some(entity) = self.entities.get_mut(id) {
+                entity.stats.hp -= suffer.amounts.iter().sum::<i32>();
+            }
+        }
+
+        let dead: Vec<(EntityId, Vec2, Option<MonsterKind>)> = self
+            .entities
+            .iter()
+            .filter(|(_, e)| e.stats.hp <= 0)
+            .map(|(id, e)| {
+                let kind = match e.class {
+                    EntityClass::Monster(monster) => Some(monster.kind),
+                    _ => None,
+                };
+                (id, e.pos, kind)
+            })
+            .collect();
+
+        let mut rng = rand::thread_rng();
+        let mut loot_drops = Vec::new();
+        for (id, pos, kind) in dead {
+            self.entities.remove(id);
+            if let Some(kind) = kind {
+                loot_drops.extend(roll_loot(&drop_table_for(kind), pos, &mut rng));
+            }
+        }
+
+        let mut unused = HashMap::new();
+        for drop in loot_drops {
+            self.apply_side_effect(drop, &mut unused);
+        }
+    }
+
+    fn update(&mut self) -> bool {
+        // As long as Entity is trivial to clone this is ok and pays dividends here: the
+        // per-entity update pass below only ever reads this snapshot and &self.map/&self.occupancy,
+        // so it's safe to run across threads with rayon.
+        let entities0 = self.entities.clone();
+        self.occupancy.rebuild(&self.map, &entities0);
+
+        let map = &self.map;
+        let occupancy = &self.occupancy;
+
+        // WASM-backed entities need exclusive, in-order access to their Store (see brain.rs), so
+        // they're stepped first and serially.
+        let mut side_effects: Vec<Vec<SideEffect>> = Vec::new();
+        for (id, e) in self.entities.iter_mut() {
+            if entity_brain(e).is_some() {
+                side_effects.push(e.update(id, &entities0, map, occupancy, &mut self.brains));
+            }
+        }
+
+        // Everything else has no brain to synchronize on, so it can run in parallel.
+        let parallel_effects: Vec<Vec<SideEffect>> = self
+            .entities
+            .par_iter_mut()
+            .filter(|(_, e)| entity_brain(e).is_none())
+            .map(|(id, e)| e.update(id, &entities0, map, occupancy, &mut []))
+            .collect();
+        side_effects.extend(parallel_effects);
+
+        // Apply side effects, queueing up any damage dealt
+        let mut suffer_damage: HashMap<EntityId, SufferDamage> = HashMap::new();
+        side_effects.iter().for_each(|eff| {
+            eff.iter().for_each(|side_effect| {
+                self.apply_side_effect(*side_effect, &mut suffer_damage);
+            })
+        });
+
+        // Last step, resolve queued damage and kill entities. Their slots are vacated and
+        // generation-bumped, so any EntityId held over from this frame (e.g. a monster's cached
+        // target) now safely resolves to None instead of aliasing whatever gets inserted into
+        // that slot next.
+        self.resolve_damage(suffer_damage);
+
+        // Keep running if there are at least 2 combatants alive. Items never die on their own and
+        // pile up as loot accumulates, so counting raw `entities.len()` would keep the game going
+        // forever once only a player and a field of dropped loot remain.
+        self.entities
+            .iter()
+            .filter(|(_, e)| entity_faction(e).is_some())
+            .count()
+            > 1
+    }
+
+}
+
+pub fn run() {
+    let mut game = Game::new();
+
+    while game.update() {}
+}
+
+// A WASM brain that requests no actions: dropped into one monster below so `benchmark()`
+// actually drives the serial brain-stepping pass
+// and the load/register pipeline, instead of those only ever running under `#[cfg(test)]`.
+const DEMO_BRAIN_WAT: &str = r#"(module (func (export "update")))"#;
+
+// Builds a `Game` with `entity_count` entities scattered across the map (roughly one player for
+// every 7 monsters, i.e. one in every 8 entities, with the first monster WASM-brained), for
+// `benchmark` below to drive.
+fn populated_game(entity_count: usize) -> Game {
+    let mut game = Game::new();
+
+    // The demo module is fixed at compile time, so a load failure here means the WASM pipeline
+    // itself is broken, not bad input; that's worth a panic rather than silently falling back to
+    // a hardcoded monster.
+    let demo_brain_id = (entity_count > 1).then(|| {
+        let engine = engine_for_brains().expect("fuel-enabled engine construction never fails");
+        let module = wasmtime::Module::new(&engine, DEMO_BRAIN_WAT)
+            .expect("DEMO_BRAIN_WAT is valid WAT");
+        let brain = Brain::load(&engine, &module, 10_000).expect("DEMO_BRAIN_WAT exports update");
+        game.register_brain(brain)
+    });
+
+    for i in 0..entity_count {
+        let pos = vec2((i % game.map.size) as f32, (i / game.map.size) as f32);
+        let stats = CombatStats {
+            max_hp: 20,
+            hp: 20,
+            defense: 1,
+            power: 4,
+        };
+
+        let class = if i % 8 == 0 {
+            EntityClass::Player(PlayerData { brain: None })
+        } else {
+            EntityClass::Monster(MonsterData {
+                ai_state: AiState::Wander,
+                faction: Faction::Hostile,
+                aggro_range: 10.0,
+                target: None,
+                brain: if i == 1 { demo_brain_id } else { None },
+                kind: MonsterKind::Rat,
+            })
+        };
+
+        game.entities.insert(Entity { pos, stats, class });
+    }
+
+    game
+}
+
+// Times `ticks` calls to `Game::update` over a freshly built `entity_count`-entity game, so the
+// rayon speedup from parallelizing the per-entity update pass is measurable and regressions are
+// caught.
+pub fn benchmark(ticks: usize, entity_count: usize) -> std::time::Duration {
+    let mut game = populated_game(entity_count);
+
+    let start = std::time::Instant::now();
+    for _ in 0..ticks {
+        game.update();
+    }
+    start.elapsed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_wall(map: &mut Map, x: usize, y: usize) {
+        let idx = y * map.size + x;
+        map.map[idx] = MapClass::Wall;
+    }
+
+    #[test]
+    fn a_star_routes_around_a_wall() {
+        let mut map = Map::new(5);
+        // Wall off the middle column except a gap at y=4, forcing the path to detour instead of
+        // cutting straight across.
+        for y in 0..4 {
+            set_wall(&mut map, 2, y);
+        }
+
+        let occupancy = Occupancy::new(&map);
+        let path = map
+            .a_star(&occupancy, vec2(0.0, 0.0), vec2(4.0, 0.0))
+            .expect("a path around the gap should exist");
+
+        assert_eq!(path.first().map(|p| (p.x, p.y)), Some((0.0, 0.0)));
+        assert_eq!(path.last().map(|p| (p.x, p.y)), Some((4.0, 0.0)));
+        assert!(path.iter().all(|p| map.validate_move(*p)));
+    }
+
+    #[test]
+    fn a_star_returns_none_when_goal_is_walled_off() {
+        let mut map = Map::new(5);
+        for y in 0..5 {
+            set_wall(&mut map, 2, y);
+        }
+
+        let occupancy = Occupancy::new(&map);
+        assert!(map.a_star(&occupancy, vec2(0.0, 0.0), vec2(4.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn validate_move_rejects_off_grid_positions() {
+        let map = Map::new(5);
+        assert!(!map.validate_move(vec2(-1.0, 0.0)));
+        assert!(!map.validate_move(vec2(5.0, 0.0)));
+        assert!(map.validate_move(vec2(0.0, 0.0)));
+    }
+
+    #[test]
+    fn damage_dealt_is_power_minus_defense_floored_at_one() {
+        let attacker = CombatStats { max_hp: 10, hp: 10, defense: 0, power: 5 };
+        let defender = CombatStats { max_hp: 10, hp: 10, defense: 2, power: 0 };
+        assert_eq!(damage_dealt(&attacker, &defender), 3);
+
+        // Defense at or above power should never heal or no-op the hit: a stray attack always
+        // chips at least 1 hp.
+        let tanky = CombatStats { max_hp: 10, hp: 10, defense: 99, power: 0 };
+        assert_eq!(damage_dealt(&attacker, &tanky), 1);
+    }
+
+    #[test]
+    fn resolve_damage_kills_entities_at_or_below_zero_hp_only() {
+        let mut game = Game::new();
+        let stats = |hp| CombatStats { max_hp: 10, hp, defense: 0, power: 0 };
+        let survivor = game.entities.insert(Entity {
+            pos: vec2(0.0, 0.0),
+            stats: stats(5),
+            class: EntityClass::Item(ItemData { kind: ItemKind::Gold }),
+        });
+        let victim = game.entities.insert(Entity {
+            pos: vec2(0.0, 0.0),
+            stats: stats(5),
+            class: EntityClass::Item(ItemData { kind: ItemKind::Gold }),
+        });
+
+        let mut suffer_damage = HashMap::new();
+        suffer_damage.entry(survivor).or_insert_with(SufferDamage::default).amounts.push(3);
+        suffer_damage.entry(victim).or_insert_with(SufferDamage::default).amounts.push(5);
+
+        game.resolve_damage(suffer_damage);
+
+        assert_eq!(game.entities.get(survivor).map(|e| e.stats.hp), Some(2));
+        assert!(game.entities.get(victim).is_none());
+    }
+
+    #[test]
+    fn pickup_heals_a_potion_picker_to_max_hp_and_removes_the_item() {
+        let mut game = Game::new();
+        let player = game.entities.insert(Entity {
+            pos: vec2(0.0, 0.0),
+            stats: CombatStats { max_hp: 20, hp: 5, defense: 0, power: 0 },
+            class: EntityClass::Player(PlayerData { brain: None }),
+        });
+        let potion = game.entities.insert(Entity {
+            pos: vec2(0.0, 0.0),
+            stats: CombatStats { max_hp: 1, hp: 1, defense: 0, power: 0 },
+            class: EntityClass::Item(ItemData { kind: ItemKind::Potion }),
+        });
+
+        let mut unused = HashMap::new();
+        game.apply_side_effect(SideEffect::Pickup { entity0: player, entity1: potion }, &mut unused);
+
+        assert_eq!(game.entities.get(player).map(|e| e.stats.hp), Some(20));
+        assert!(game.entities.get(potion).is_none());
+    }
+
+    #[test]
+    fn pickup_of_non_potion_items_collects_without_healing() {
+        let mut game = Game::new();
+        let player = game.entities.insert(Entity {
+            pos: vec2(0.0, 0.0),
+            stats: CombatStats { max_hp: 20, hp: 5, defense: 0, power: 0 },
+            class: EntityClass::Player(PlayerData { brain: None }),
+        });
+        let gold = game.entities.insert(Entity {
+            pos: vec2(0.0, 0.0),
+            stats: CombatStats { max_hp: 1, hp: 1, defense: 0, power: 0 },
+            class: EntityClass::Item(ItemData { kind: ItemKind::Gold }),
+        });
+
+        let mut unused = HashMap::new();
+        game.apply_side_effect(SideEffect::Pickup { entity0: player, entity1: gold }, &mut unused);
+
+        assert_eq!(game.entities.get(player).map(|e| e.stats.hp), Some(5));
+        assert!(game.entities.get(gold).is_none());
+    }
+
+    #[test]
+    fn nearest_hostile_skips_same_faction_and_finds_cross_faction_targets() {
+        let mut game = Game::new();
+        let stats = CombatStats { max_hp: 10, hp: 10, defense: 0, power: 0 };
+        let monster = |faction| Entity {
+            pos: vec2(0.0, 0.0),
+            stats,
+            class: EntityClass::Monster(MonsterData {
+                ai_state: AiState::Wander,
+                faction,
+                aggro_range: 10.0,
+                target: None,
+                brain: None,
+                kind: MonsterKind::Rat,
+            }),
+        };
+
+        let seeker = game.entities.insert(Entity { pos: vec2(0.0, 0.0), ..monster(Faction::Hostile) });
+        let ally = game.entities.insert(Entity { pos: vec2(1.0, 0.0), ..monster(Faction::Hostile) });
+        let enemy = game.entities.insert(Entity { pos: vec2(2.0, 0.0), ..monster(Faction::Player) });
+
+        game.occupancy.rebuild(&game.map, &game.entities);
+
+        let found = nearest_hostile(
+            vec2(0.0, 0.0),
+            seeker,
+            Faction::Hostile,
+            &game.entities,
+            &game.occupancy,
+            &game.map,
+            10.0,
+        );
+
+        // The same-faction ally is closer but must never be picked; only the cross-faction enemy
+        // qualifies as hostile.
+        assert_eq!(found.map(|(id, _, _)| id), Some(enemy));
+        assert_ne!(found.map(|(id, _, _)| id), Some(ally));
+    }
+
+    #[test]
+    fn update_steps_a_mixed_brain_and_scripted_population_without_panicking() {
+        let mut game = Game::new();
+        let stats = CombatStats { max_hp: 20, hp: 20, defense: 1, power: 4 };
+
+        // A hardcoded monster with no brain at all...
+        let scripted_monster = game.entities.insert(Entity {
+            pos: vec2(5.0, 5.0),
+            stats,
+            class: EntityClass::Monster(MonsterData {
+                ai_state: AiState::Wander,
+                faction: Faction::Hostile,
+                aggro_range: 5.0,
+                target: None,
+                brain: None,
+                kind: MonsterKind::Rat,
+            }),
+        });
+
+        // ...and a WASM-backed player, so update() has to drive both the serial brain pass and
+        // the parallel scripted pass in the same tick.
+        let engine = crate::brain::engine_for_brains().unwrap();
+        let module = wasmtime::Module::new(&engine, r#"(module (func (export "update")))"#).unwrap();
+        let brain_id = game.register_brain(Brain::load(&engine, &module, 10_000).unwrap());
+        let brained_player = game.entities.insert(Entity {
+            pos: vec2(6.0, 5.0),
+            stats,
+            class: EntityClass::Player(PlayerData { brain: Some(brain_id) }),
+        });
+
+        game.update();
+
+        // The no-op brain never moves, and the scripted monster's melee state (it's already
+        // within MELEE_DISTANCE) holds its position too, so both entities should still be
+        // sitting where the spatial index put them.
+        game.occupancy.rebuild(&game.map, &game.entities);
+        let nearby = game
+            .occupancy
+            .entities_in_radius(&game.map, &game.entities, vec2(6.0, 5.0), 5.0);
+        assert!(nearby.contains(&scripted_monster));
+        assert!(nearby.contains(&brained_player));
+    }
+
+    #[test]
+    fn update_closes_distance_between_a_monster_and_a_far_away_target() {
+        let mut game = Game::new();
+        let stats = CombatStats { max_hp: 20, hp: 20, defense: 1, power: 4 };
+
+        let player = game.entities.insert(Entity {
+            pos: vec2(10.0, 0.0),
+            stats,
+            class: EntityClass::Player(PlayerData { brain: None }),
+        });
+        let monster = game.entities.insert(Entity {
+            pos: vec2(0.0, 0.0),
+            stats,
+            class: EntityClass::Monster(MonsterData {
+                ai_state: AiState::Wander,
+                faction: Faction::Hostile,
+                aggro_range: 20.0,
+                target: None,
+                brain: None,
+                kind: MonsterKind::Rat,
+            }),
+        });
+
+        let start_dist = game.entities.get(monster).unwrap().pos.distance(game.entities.get(player).unwrap().pos);
+
+        for _ in 0..5 {
+            game.update();
+        }
+
+        let end_dist = game.entities.get(monster).unwrap().pos.distance(game.entities.get(player).unwrap().pos);
+
+        // Regression: a_star used to reject the goal tile itself as occupancy-blocked (the
+        // target always stands on it), so it could never find a path to a non-adjacent target
+        // and the monster would sit at its spawn point forever.
+        assert!(end_dist < start_dist, "start_dist={start_dist}, end_dist={end_dist}");
+    }
+
+    #[test]
+    fn roll_loot_always_yields_exactly_one_common_item() {
+        // Every monster's table has a non-zero total common weight and a rare pool disjoint from
+        // it, so each roll should land exactly one common-pool drop, never zero or two.
+        for kind in [MonsterKind::Rat, MonsterKind::Goblin, MonsterKind::Troll] {
+            let table = drop_table_for(kind);
+            let common_discriminants: Vec<_> = table
+                .common
+                .iter()
+                .map(|(item, _)| std::mem::discriminant(item))
+                .collect();
+            let pos = vec2(3.0, 4.0);
+            let mut rng = rand::thread_rng();
+
+            for _ in 0..200 {
+                let drops = roll_loot(&table, pos, &mut rng);
+                let common_count = drops
+                    .iter()
+                    .filter(|d| {
+                        matches!(d, SideEffect::SpawnItem { item, .. }
+                            if common_discriminants.contains(&std::mem::discriminant(item)))
+                    })
+                    .count();
+                assert_eq!(common_count, 1);
+                assert!(drops
+                    .iter()
+                    .all(|d| matches!(d, SideEffect::SpawnItem { pos: p, .. } if (p.x, p.y) == (pos.x, pos.y))));
+            }
+        }
+    }
+
+    #[test]
+    fn roll_loot_fires_a_rare_drop_when_its_roll_is_one_in_one() {
+        use rand::SeedableRng;
+
+        // A 1-in-1 rare entry always fires regardless of what the RNG produces, since
+        // `gen_range(0..1)` only has one possible outcome. Taking `&mut impl Rng` lets the test
+        // pin this down with a seeded rng instead of looping on `thread_rng()` and hoping.
+        let table = DropTable {
+            common: vec![(ItemKind::Gold, 1)],
+            rare: vec![(ItemKind::Gem, 1)],
+        };
+        let pos = vec2(1.0, 2.0);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+
+        let drops = roll_loot(&table, pos, &mut rng);
+
+        assert!(drops
+            .iter()
+            .any(|d| matches!(d, SideEffect::SpawnItem { item: ItemKind::Gem, .. })));
+    }
+}